@@ -1,5 +1,8 @@
 extern crate mudtcp;
-use mudtcp::{codec::LineCodec, *};
+use mudtcp::{
+    codec::{CodecRegistry, LineCodec, WebSocketCodec},
+    *,
+};
 
 const MOTD: &str = "Welcome to the chat server!";
 
@@ -11,8 +14,17 @@ fn main() {
         return;
     }
 
+    let mut registry = CodecRegistry::new();
+    registry.register("/mudtcp/line/1.0", |id, stream| {
+        LineCodec::new(id, stream).map(|c| Box::new(c) as Box<dyn Codec>)
+    });
+    registry.register_http_upgrade("/mudtcp/websocket/1.0", |id, stream| {
+        WebSocketCodec::new(id, stream).map(|c| Box::new(c) as Box<dyn Codec>)
+    });
+
     let addr = format!("{}:{}", args[1], args[2]);
-    let mut server = Server::<LineCodec>::new(addr.as_str()).expect("Failed to create server");
+    let server =
+        Server::new(addr.as_str(), registry, Some(64)).expect("Failed to create server");
     println!("Server started with address \"{addr}\"");
 
     loop {
@@ -24,6 +36,9 @@ fn main() {
                     println!("Event: Client joined with id {id}.");
                     msg_out.push((id, MOTD.to_owned()));
                 }
+                Event::Negotiated(id, protocol) => {
+                    println!("Event: Client with id {id} negotiated protocol \"{protocol}\".");
+                }
                 Event::Leave(id) => {
                     println!("Event: Client with id {id} left.");
                 }
@@ -35,7 +50,16 @@ fn main() {
                     );
                     msg_in.push(msg);
                 }
+                Event::ReceiveChunk(id, data, last) => {
+                    println!(
+                        "Event: Received {}-byte chunk (last: {last}) from Client with id {id}.",
+                        data.len(),
+                    );
+                }
                 Event::Send(_) => {}
+                Event::Rejected(addr) => {
+                    println!("Event: Rejected connection from {addr}: server full.");
+                }
                 Event::ClientError((id, e)) => {
                     println!("Event: Client with id {id} error: {e}.");
                 }
@@ -1,12 +1,33 @@
 mod linecodec;
+mod registry;
+mod websocketcodec;
 pub use linecodec::LineCodec;
+pub use registry::CodecRegistry;
+pub(crate) use registry::{Advance, Negotiation};
+pub use websocketcodec::WebSocketCodec;
 use crate::ClientId;
+use bytes::Bytes;
+use std::os::unix::io::RawFd;
 
-pub trait Codec: Sized {
-    fn new(id: ClientId, stream: std::net::TcpStream) -> std::io::Result<Self>;
-    fn read(&mut self) -> std::io::Result<String>;
+/// What a `Codec::read` call yielded: either a complete textual line, or one chunk of a
+/// streamed body started by the peer (see `Server::enqueue_stream`).
+#[derive(Debug)]
+pub enum Incoming {
+    Line(String),
+    Chunk { data: Bytes, last: bool },
+}
+
+pub trait Codec {
+    fn new(id: ClientId, stream: std::net::TcpStream) -> std::io::Result<Self>
+    where
+        Self: Sized;
+    fn read(&mut self) -> std::io::Result<Incoming>;
     fn write(&mut self, message: &str) -> std::io::Result<()>;
+    /// Writes one chunk of a streamed body. `last` marks the chunk that ends the stream.
+    fn write_chunk(&mut self, data: &Bytes, last: bool) -> std::io::Result<()>;
     fn shutdown(&mut self);
     fn is_open(&self) -> bool;
     fn id(&self) -> ClientId;
+    /// Raw fd of the underlying stream, used to register/deregister with the server's mio poll.
+    fn raw_fd(&self) -> RawFd;
 }
@@ -1,40 +1,150 @@
-use crate::{codec::Codec, ClientId};
+use crate::{
+    codec::{Codec, Incoming},
+    ClientId,
+};
+use bytes::Bytes;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::str::FromStr;
 use std::{
-    io::{self, BufRead, Write},
+    io::{self, BufRead, Read, Write},
     net,
 };
 
+/// Lead byte of a framed chunk message; never appears in [`Incoming::Line`] content since
+/// [`LineCodec::read`] strips ASCII control codes from line text before returning it.
+const FRAME_ESCAPE: u8 = 0x01;
+const TAG_CHUNK_START: u8 = 0;
+const TAG_CHUNK_DATA: u8 = 1;
+const TAG_CHUNK_END: u8 = 2;
+
+/// Caps a single `TAG_CHUNK_DATA` payload so a peer can't force unbounded buffering of
+/// `chunk_buf` by declaring a multi-gigabyte chunk and trickling bytes in behind it.
+const MAX_CHUNK_LEN: usize = 16 * 1024 * 1024;
+
+enum ChunkFrame {
+    Start,
+    Data(Vec<u8>),
+    End,
+}
+
 /// Line-based codec that assumes [ASCII](https://www.ascii-code.com/ASCII) encoding.
 ///
 /// [C0 control codes](https://wikipedia.org/wiki/C0_and_C1_control_codes#C0_controls) and sequences are
 /// stripped out and ignored. Leading / trailing whitespace is removed.
 /// For example (underscores representing whitespace): `__fo^X^Ao_b^[[1;5Aar_` becomes `foo_bar`.
+///
+/// Streamed bodies (see [`Codec::write_chunk`]) are carried out-of-band from regular lines,
+/// framed behind a leading [`FRAME_ESCAPE`] byte that can't occur in sanitized line text.
 #[derive(Debug)]
 pub struct LineCodec {
     reader: io::BufReader<net::TcpStream>,
-    writer: io::LineWriter<net::TcpStream>,
+    writer: net::TcpStream,
     open: bool,
     id: ClientId,
+    chunk_buf: Vec<u8>,
+    writing_stream: bool,
+    reading_stream: bool,
+    /// Bytes of the message currently being sent that the nonblocking socket hasn't accepted
+    /// yet. `write`/`write_chunk` resume from here instead of resubmitting from byte 0, since
+    /// a partially-accepted `write_all` can't be safely retried with the original buffer
+    /// without duplicating whatever prefix already reached the wire.
+    write_buf: Vec<u8>,
 }
 
 impl Codec for LineCodec {
     fn new(id: ClientId, stream: net::TcpStream) -> io::Result<Self> {
-        let writer = io::LineWriter::new(stream.try_clone()?);
+        let writer = stream.try_clone()?;
         let reader = io::BufReader::new(stream);
         Ok(Self {
             reader,
             writer,
             open: true,
             id,
+            chunk_buf: vec![],
+            writing_stream: false,
+            reading_stream: false,
+            write_buf: vec![],
         })
     }
 
-    fn read(&mut self) -> io::Result<String> {
+    fn read(&mut self) -> io::Result<Incoming> {
         if !self.open {
             return Err(io::ErrorKind::NotConnected.into());
         }
 
+        if !self.chunk_buf.is_empty() {
+            return self.read_chunk_frame();
+        }
+
+        let peek = self.reader.fill_buf()?;
+        if peek.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        if peek[0] == FRAME_ESCAPE {
+            self.read_chunk_frame()
+        } else {
+            self.read_line_frame().map(Incoming::Line)
+        }
+    }
+
+    fn write(&mut self, msg: &str) -> io::Result<()> {
+        if !self.open {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        if self.write_buf.is_empty() {
+            self.write_buf.extend_from_slice(msg.as_bytes());
+            self.write_buf.push(b'\n');
+        }
+        self.flush_write_buf()
+    }
+
+    fn write_chunk(&mut self, data: &Bytes, last: bool) -> io::Result<()> {
+        if !self.open {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        if self.write_buf.is_empty() {
+            if !self.writing_stream {
+                self.write_buf
+                    .extend_from_slice(&[FRAME_ESCAPE, TAG_CHUNK_START]);
+                self.writing_stream = true;
+            }
+            self.write_buf.push(FRAME_ESCAPE);
+            self.write_buf.push(TAG_CHUNK_DATA);
+            self.write_buf
+                .extend_from_slice(&(data.len() as u32).to_be_bytes());
+            self.write_buf.extend_from_slice(data);
+            if last {
+                self.write_buf.push(FRAME_ESCAPE);
+                self.write_buf.push(TAG_CHUNK_END);
+            }
+        }
+        self.flush_write_buf()?;
+        if last {
+            self.writing_stream = false;
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        self.open = false;
+    }
+
+    fn is_open(&self) -> bool {
+        self.open
+    }
+
+    fn id(&self) -> ClientId {
+        self.id
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.reader.get_ref().as_raw_fd()
+    }
+}
+
+impl LineCodec {
+    fn read_line_frame(&mut self) -> io::Result<String> {
         let mut buf = String::new();
         match self.reader.read_line(&mut buf) {
             Ok(bytes_read) if bytes_read > 0 => {
@@ -78,24 +188,103 @@ impl Codec for LineCodec {
         }
     }
 
-    fn write(&mut self, msg: &str) -> io::Result<()> {
-        if !self.open {
-            return Err(io::ErrorKind::NotConnected.into());
+    /// Drains whatever bytes are currently available into `chunk_buf` and decodes frames out
+    /// of it in an explicit loop (not recursion — a flood of buffered `ChunkStart` frames
+    /// must not grow the call stack) until one yields an `Incoming` or the buffer runs dry.
+    /// Partial frames are left in `chunk_buf` so reassembly survives `WouldBlock`.
+    fn read_chunk_frame(&mut self) -> io::Result<Incoming> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.reader.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.chunk_buf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
         }
-        self.writer.write_all(msg.as_bytes())?;
-        self.writer.write_all(&[b'\n'])?;
-        Ok(())
-    }
 
-    fn shutdown(&mut self) {
-        self.open = false;
+        loop {
+            match decode_chunk_frame(&self.chunk_buf)? {
+                Some((frame, consumed)) => {
+                    self.chunk_buf.drain(..consumed);
+                    match frame {
+                        ChunkFrame::Start => {
+                            if self.reading_stream {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    "received ChunkStart while already mid-stream",
+                                ));
+                            }
+                            self.reading_stream = true;
+                            /* Carries no payload of its own; keep decoding in case the
+                             * first chunk arrived in the same read. */
+                        }
+                        ChunkFrame::Data(payload) => {
+                            return Ok(Incoming::Chunk {
+                                data: Bytes::from(payload),
+                                last: false,
+                            });
+                        }
+                        ChunkFrame::End => {
+                            self.reading_stream = false;
+                            return Ok(Incoming::Chunk {
+                                data: Bytes::new(),
+                                last: true,
+                            });
+                        }
+                    }
+                }
+                None => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
     }
 
-    fn is_open(&self) -> bool {
-        self.open
+    /// Writes as much of `write_buf` as the nonblocking socket currently accepts, draining
+    /// the sent prefix as it goes. Returns `Ok(())` once it's all out, or `Err(WouldBlock)`
+    /// with the unsent remainder kept in `write_buf` for the next call to resume.
+    fn flush_write_buf(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.writer.write(&self.write_buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => drop(self.write_buf.drain(..n)),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
     }
+}
 
-    fn id(&self) -> ClientId {
-        self.id
+/// Parses one chunk frame out of `buf`, or `None` if it's not yet complete.
+/// Layout: `ESCAPE tag ...`; `Start` and `End` carry nothing further, `Data` carries a `u32`
+/// length prefix capped at [`MAX_CHUNK_LEN`].
+fn decode_chunk_frame(buf: &[u8]) -> io::Result<Option<(ChunkFrame, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    match buf[1] {
+        TAG_CHUNK_START => Ok(Some((ChunkFrame::Start, 2))),
+        TAG_CHUNK_DATA => {
+            if buf.len() < 6 {
+                return Ok(None);
+            }
+            let mut len_bytes = [0u8; 4];
+            len_bytes.copy_from_slice(&buf[2..6]);
+            let len = u32::from_be_bytes(len_bytes) as usize;
+            if len > MAX_CHUNK_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chunk of {len} bytes exceeds the {MAX_CHUNK_LEN}-byte max"),
+                ));
+            }
+            if buf.len() < 6 + len {
+                return Ok(None);
+            }
+            Ok(Some((ChunkFrame::Data(buf[6..6 + len].to_vec()), 6 + len)))
+        }
+        TAG_CHUNK_END => Ok(Some((ChunkFrame::End, 2))),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown chunk frame tag",
+        )),
     }
 }
@@ -0,0 +1,257 @@
+use crate::codec::Codec;
+use crate::ClientId;
+use std::io::{self, Read, Write};
+use std::net;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+type Factory = Box<dyn Fn(ClientId, net::TcpStream) -> io::Result<Box<dyn Codec>>>;
+
+/// Caps how long a [`Negotiation`] may take on a single client, so a connection that never
+/// sends its chosen protocol can't squat on a slab slot forever. Unlike a blocking read
+/// timeout, this is only checked between poll ticks, so it never stalls any other client.
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Maps protocol identifiers (e.g. `/mudtcp/line/1.0`) to the [`Codec`] they instantiate.
+///
+/// Modeled on multistream-select: [`Server`](crate::Server) offers every registered protocol
+/// to a newly accepted client and builds whichever one the client picks. The offer is only
+/// written once the client's first four bytes have arrived (they are peeked to tell an HTTP
+/// upgrade apart), so a client that waits for the offer before sending anything is never
+/// offered it and is dropped when its negotiation times out. A browser
+/// can't participate in that preamble, so a codec registered via
+/// [`register_http_upgrade`](Self::register_http_upgrade) is instead selected by sniffing
+/// for a raw HTTP `GET` request and skipping negotiation entirely.
+#[derive(Default)]
+pub struct CodecRegistry {
+    factories: Vec<(String, Factory)>,
+    http_upgrade: Option<(String, Factory)>,
+}
+
+impl CodecRegistry {
+    pub fn new() -> Self {
+        Self {
+            factories: vec![],
+            http_upgrade: None,
+        }
+    }
+
+    /// Registers a protocol identifier and the factory used to build its `Codec`.
+    pub fn register<F>(&mut self, protocol: &str, factory: F) -> &mut Self
+    where
+        F: Fn(ClientId, net::TcpStream) -> io::Result<Box<dyn Codec>> + 'static,
+    {
+        self.factories.push((protocol.to_owned(), Box::new(factory)));
+        self
+    }
+
+    /// Registers a codec that speaks its own HTTP upgrade handshake (e.g. `WebSocketCodec`).
+    /// Connections opening with a bare HTTP `GET` line are routed straight to it, bypassing
+    /// the multistream-select preamble that a real browser can't be made to send.
+    pub fn register_http_upgrade<F>(&mut self, protocol: &str, factory: F) -> &mut Self
+    where
+        F: Fn(ClientId, net::TcpStream) -> io::Result<Box<dyn Codec>> + 'static,
+    {
+        self.http_upgrade = Some((protocol.to_owned(), Box::new(factory)));
+        self
+    }
+
+    fn contains(&self, protocol: &str) -> bool {
+        self.factories.iter().any(|(p, _)| p == protocol)
+    }
+
+    fn build(&self, protocol: &str, id: ClientId, stream: net::TcpStream) -> io::Result<Box<dyn Codec>> {
+        match self.factories.iter().find(|(p, _)| p == protocol) {
+            Some((_, factory)) => factory(id, stream),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no codec registered for protocol `{protocol}`"),
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum NegotiationState {
+    /// Waiting for the first bytes so a raw HTTP upgrade can be told apart from a
+    /// multistream-select client. Uses `peek`, so nothing is consumed from the stream — an
+    /// HTTP-upgrade codec runs its own handshake over the untouched bytes.
+    Sniffing,
+    /// Not HTTP: offering every registered protocol, one per line, buffered so a nonblocking
+    /// partial write is resumed rather than resubmitted from scratch.
+    Offering { write_buf: Vec<u8> },
+    /// Offer sent; accumulating bytes until the client's chosen-protocol frame is complete.
+    AwaitingChoice { read_buf: Vec<u8> },
+    /// Chosen protocol is being echoed back to confirm it.
+    Confirming { write_buf: Vec<u8>, protocol: String },
+}
+
+/// An in-progress multistream-select (or HTTP-upgrade sniff) handshake for one freshly
+/// accepted connection.
+///
+/// `advance` never waits on the socket — it reads and writes only what the stream's *current*
+/// readiness allows and returns [`Advance::Pending`] the moment that's exhausted.
+/// [`Server`](crate::Server) drives it forward from the same mio poll loop that services every
+/// other client, so one slow or idle connect can't stall everyone else's reads and writes.
+pub(crate) struct Negotiation {
+    id: ClientId,
+    stream: net::TcpStream,
+    state: NegotiationState,
+    deadline: Instant,
+}
+
+pub(crate) enum Advance {
+    Pending(Negotiation),
+    Done(Box<dyn Codec>, String),
+}
+
+impl Negotiation {
+    pub(crate) fn new(id: ClientId, stream: net::TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            id,
+            stream,
+            state: NegotiationState::Sniffing,
+            deadline: Instant::now() + NEGOTIATION_TIMEOUT,
+        })
+    }
+
+    pub(crate) fn raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// Drives the handshake as far as the stream's current readiness allows. Returns
+    /// `Advance::Pending` (holding `self` back) if it needs another readiness event to make
+    /// progress, or `Advance::Done` once a `Codec` has been built.
+    pub(crate) fn advance(mut self, registry: &CodecRegistry) -> io::Result<Advance> {
+        loop {
+            match self.state {
+                NegotiationState::Sniffing => {
+                    let mut buf = [0u8; 4];
+                    let n = match self.stream.peek(&mut buf) {
+                        Ok(n) => n,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            return Ok(Advance::Pending(self));
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    if n < buf.len() {
+                        return Ok(Advance::Pending(self));
+                    }
+
+                    if let Some((protocol, factory)) = &registry.http_upgrade {
+                        if &buf == b"GET " {
+                            let codec = factory(self.id, self.stream)?;
+                            return Ok(Advance::Done(codec, protocol.clone()));
+                        }
+                    }
+
+                    let mut write_buf = vec![];
+                    for (protocol, _) in &registry.factories {
+                        write_offer(&mut write_buf, protocol);
+                    }
+                    self.state = NegotiationState::Offering { write_buf };
+                }
+                NegotiationState::Offering { ref mut write_buf } => {
+                    if !try_flush(&mut self.stream, write_buf)? {
+                        return Ok(Advance::Pending(self));
+                    }
+                    self.state = NegotiationState::AwaitingChoice { read_buf: vec![] };
+                }
+                NegotiationState::AwaitingChoice { ref mut read_buf } => {
+                    let mut buf = [0u8; 512];
+                    loop {
+                        match self.stream.read(&mut buf) {
+                            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                            Ok(n) => read_buf.extend_from_slice(&buf[..n]),
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => return Err(e),
+                        }
+                    }
+
+                    let (chosen, consumed) = match decode_choice_frame(read_buf)? {
+                        Some(frame) => frame,
+                        None => return Ok(Advance::Pending(self)),
+                    };
+                    read_buf.drain(..consumed);
+
+                    if !registry.contains(&chosen) {
+                        let mut reject = b"na\n".to_vec();
+                        let _ = try_flush(&mut self.stream, &mut reject);
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("client requested unknown protocol `{chosen}`"),
+                        ));
+                    }
+
+                    let mut write_buf = vec![];
+                    write_offer(&mut write_buf, &chosen);
+                    self.state = NegotiationState::Confirming {
+                        write_buf,
+                        protocol: chosen,
+                    };
+                }
+                NegotiationState::Confirming {
+                    ref mut write_buf,
+                    ref protocol,
+                } => {
+                    if !try_flush(&mut self.stream, write_buf)? {
+                        return Ok(Advance::Pending(self));
+                    }
+                    let protocol = protocol.clone();
+                    let codec = registry.build(&protocol, self.id, self.stream)?;
+                    return Ok(Advance::Done(codec, protocol));
+                }
+            }
+        }
+    }
+}
+
+/// Writes as much of `buf` as the nonblocking stream currently accepts, draining the sent
+/// prefix as it goes. Returns `true` once `buf` is fully flushed, `false` if it would block
+/// (the unsent remainder stays in `buf` for the next attempt).
+fn try_flush(stream: &mut net::TcpStream, buf: &mut Vec<u8>) -> io::Result<bool> {
+    while !buf.is_empty() {
+        match stream.write(buf) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => drop(buf.drain(..n)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn write_offer(buf: &mut Vec<u8>, protocol: &str) {
+    buf.extend_from_slice(protocol.len().to_string().as_bytes());
+    buf.push(b'\n');
+    buf.extend_from_slice(protocol.as_bytes());
+    buf.push(b'\n');
+}
+
+/// Parses one `<len>\n<protocol>\n` frame out of `buf`, or `None` if it's not yet complete.
+fn decode_choice_frame(buf: &[u8]) -> io::Result<Option<(String, usize)>> {
+    let len_end = match buf.iter().position(|&b| b == b'\n') {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let len: usize = std::str::from_utf8(&buf[..len_end])
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "malformed protocol frame length")
+        })?;
+
+    let body_start = len_end + 1;
+    if buf.len() < body_start + len + 1 {
+        return Ok(None);
+    }
+
+    let protocol = String::from_utf8(buf[body_start..body_start + len].to_vec())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "protocol identifier not utf8"))?;
+    Ok(Some((protocol, body_start + len + 1)))
+}
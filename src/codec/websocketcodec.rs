@@ -0,0 +1,422 @@
+use crate::{
+    codec::{Codec, Incoming},
+    ClientId,
+};
+use bytes::Bytes;
+use std::io::{self, Read, Write};
+use std::net;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// GUID from [RFC 6455 §1.3](https://www.rfc-editor.org/rfc/rfc6455#section-1.3), appended to
+/// the client's `Sec-WebSocket-Key` before hashing to produce `Sec-WebSocket-Accept`.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Handshake {
+    Pending,
+    Done,
+}
+
+/// Codec speaking the WebSocket wire protocol ([RFC 6455](https://www.rfc-editor.org/rfc/rfc6455)),
+/// so browser clients can connect without a native TCP client.
+///
+/// `new()` only stashes the stream; the HTTP upgrade handshake itself happens across
+/// subsequent `read()` calls so a nonblocking partial read never blocks the event loop.
+#[derive(Debug)]
+pub struct WebSocketCodec {
+    stream: net::TcpStream,
+    id: ClientId,
+    open: bool,
+    handshake: Handshake,
+    handshake_buf: Vec<u8>,
+    read_buf: Vec<u8>,
+    fragment: Vec<u8>,
+    /// Opcode the in-progress fragmented message started with (`OP_TEXT` or `OP_BINARY`),
+    /// so continuation frames know how to interpret `fragment`.
+    fragment_opcode: u8,
+    writing_stream: bool,
+    /// Bytes of the frame currently being sent that the nonblocking socket hasn't accepted
+    /// yet. `write_frame` resumes from here instead of resubmitting from byte 0, since a
+    /// partially-accepted `write_all` can't be safely retried with the original buffer
+    /// without duplicating whatever prefix already reached the wire.
+    write_buf: Vec<u8>,
+}
+
+impl Codec for WebSocketCodec {
+    fn new(id: ClientId, stream: net::TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            stream,
+            id,
+            open: true,
+            handshake: Handshake::Pending,
+            handshake_buf: vec![],
+            read_buf: vec![],
+            fragment: vec![],
+            fragment_opcode: OP_TEXT,
+            writing_stream: false,
+            write_buf: vec![],
+        })
+    }
+
+    fn read(&mut self) -> io::Result<Incoming> {
+        if !self.open {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+
+        if self.handshake == Handshake::Pending {
+            self.continue_handshake()?;
+            /* A client that sends its first frame back-to-back with the upgrade request may
+             * never send anything else, so the fd won't necessarily become readable again —
+             * any trailing bytes `continue_handshake` already pulled off the wire must be
+             * parsed now rather than left to wait for a readiness event that never comes. */
+            if self.handshake == Handshake::Pending || self.read_buf.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+        } else {
+            self.fill_read_buf()?;
+        }
+
+        self.parse_frame()
+    }
+
+    fn write(&mut self, message: &str) -> io::Result<()> {
+        if !self.open {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        self.write_frame(OP_TEXT, true, message.as_bytes())
+    }
+
+    fn write_chunk(&mut self, data: &Bytes, last: bool) -> io::Result<()> {
+        if !self.open {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let opcode = if self.writing_stream {
+            OP_CONTINUATION
+        } else {
+            OP_BINARY
+        };
+        self.write_frame(opcode, last, data)?;
+        self.writing_stream = !last;
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        if self.open && self.handshake == Handshake::Done {
+            let _ = self.write_frame(OP_CLOSE, true, &[]);
+        }
+        self.open = false;
+    }
+
+    fn is_open(&self) -> bool {
+        self.open
+    }
+
+    fn id(&self) -> ClientId {
+        self.id
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl WebSocketCodec {
+    /// Reads whatever of the HTTP upgrade request is currently available, accumulating
+    /// across calls until the blank line terminating the header block arrives.
+    fn continue_handshake(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 512];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.handshake_buf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let header_end = match find(&self.handshake_buf, b"\r\n\r\n") {
+            Some(pos) => pos + 4,
+            None => return Ok(()),
+        };
+
+        let request = String::from_utf8_lossy(&self.handshake_buf[..header_end]).into_owned();
+        let key = request
+            .lines()
+            .find_map(|line| line.strip_prefix("Sec-WebSocket-Key:"))
+            .map(|v| v.trim().to_owned())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "missing Sec-WebSocket-Key header")
+            })?;
+
+        let accept = base64_encode(&sha1(format!("{key}{GUID}").as_bytes()));
+        write!(
+            self.stream,
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {accept}\r\n\r\n"
+        )?;
+        self.stream.flush()?;
+        self.handshake = Handshake::Done;
+
+        /* Bytes sent back-to-back with the upgrade request (e.g. the client's first WS
+         * frame, coalesced by the OS into the same read) land past the header terminator
+         * and belong to the frame stream, not the handshake — hand them to `read_buf`
+         * instead of dropping them. */
+        let trailing = self.handshake_buf.split_off(header_end);
+        self.read_buf.extend_from_slice(&trailing);
+        Ok(())
+    }
+
+    fn fill_read_buf(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.read_buf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Decodes frames out of `read_buf`, answering control frames transparently. Text frames
+    /// are coalesced across continuations into a complete [`Incoming::Line`]; binary frames
+    /// are handed back one at a time as [`Incoming::Chunk`], since a streamed body is meant to
+    /// be consumed incrementally rather than buffered whole.
+    fn parse_frame(&mut self) -> io::Result<Incoming> {
+        loop {
+            let frame = match decode_frame(&self.read_buf) {
+                Some(frame) => frame,
+                None => return Err(io::ErrorKind::WouldBlock.into()),
+            };
+            self.read_buf.drain(..frame.consumed);
+
+            match frame.opcode {
+                OP_PING => self.write_frame(OP_PONG, true, &frame.payload)?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    let _ = self.write_frame(OP_CLOSE, true, &[]);
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                OP_BINARY => {
+                    self.fragment_opcode = OP_BINARY;
+                    return Ok(Incoming::Chunk {
+                        data: Bytes::from(frame.payload),
+                        last: frame.fin,
+                    });
+                }
+                OP_CONTINUATION if self.fragment_opcode == OP_BINARY => {
+                    return Ok(Incoming::Chunk {
+                        data: Bytes::from(frame.payload),
+                        last: frame.fin,
+                    });
+                }
+                OP_CONTINUATION | OP_TEXT => {
+                    if frame.opcode == OP_TEXT {
+                        self.fragment_opcode = OP_TEXT;
+                    }
+                    self.fragment.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        let text = String::from_utf8(std::mem::take(&mut self.fragment))
+                            .map_err(|_| {
+                                io::Error::new(io::ErrorKind::InvalidData, "frame not utf8")
+                            })?;
+                        return Ok(Incoming::Line(text));
+                    }
+                }
+                _ => { /* ignore unsupported opcodes */ }
+            }
+        }
+    }
+
+    fn write_frame(&mut self, opcode: u8, fin: bool, payload: &[u8]) -> io::Result<()> {
+        if self.write_buf.is_empty() {
+            self.write_buf
+                .push((if fin { 0x80 } else { 0x00 }) | opcode);
+            let len = payload.len();
+            if len < 126 {
+                self.write_buf.push(len as u8);
+            } else if len <= u16::MAX as usize {
+                self.write_buf.push(126);
+                self.write_buf.extend_from_slice(&(len as u16).to_be_bytes());
+            } else {
+                self.write_buf.push(127);
+                self.write_buf.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+            self.write_buf.extend_from_slice(payload);
+        }
+        self.flush_write_buf()
+    }
+
+    /// Writes as much of `write_buf` as the nonblocking socket currently accepts, draining
+    /// the sent prefix as it goes. Returns `Ok(())` once it's all out, or `Err(WouldBlock)`
+    /// with the unsent remainder kept in `write_buf` for the next call to resume.
+    fn flush_write_buf(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => drop(self.write_buf.drain(..n)),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+    consumed: usize,
+}
+
+fn decode_frame(buf: &[u8]) -> Option<Frame> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let fin = buf[0] & 0x80 != 0;
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+    let mut len = (buf[1] & 0x7f) as usize;
+    let mut offset = 2;
+
+    if len == 126 {
+        if buf.len() < offset + 2 {
+            return None;
+        }
+        len = u16::from_be_bytes([buf[offset], buf[offset + 1]]) as usize;
+        offset += 2;
+    } else if len == 127 {
+        if buf.len() < offset + 8 {
+            return None;
+        }
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&buf[offset..offset + 8]);
+        len = u64::from_be_bytes(bytes) as usize;
+        offset += 8;
+    }
+
+    let mask = if masked {
+        if buf.len() < offset + 4 {
+            return None;
+        }
+        let key = [buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]];
+        offset += 4;
+        Some(key)
+    } else {
+        None
+    };
+
+    if buf.len() < offset + len {
+        return None;
+    }
+
+    let mut payload = buf[offset..offset + len].to_vec();
+    if let Some(key) = mask {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= key[i % 4];
+        }
+    }
+
+    Some(Frame {
+        fin,
+        opcode,
+        payload,
+        consumed: offset + len,
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Minimal SHA-1 ([RFC 3174](https://www.rfc-editor.org/rfc/rfc3174)), just enough to compute
+/// `Sec-WebSocket-Accept`. Not constant-time; this input isn't secret.
+fn sha1(message: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut data = message.to_vec();
+    let bit_len = (message.len() as u64) * 8;
+    data.push(0x80);
+    while data.len() % 64 != 56 {
+        data.push(0);
+    }
+    data.extend_from_slice(&bit_len.to_be_bytes());
+
+    for chunk in data.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | ((!b) & d), 0x5A827999u32),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b0 = chunk[0];
+        let b1 = *chunk.get(1).unwrap_or(&0);
+        let b2 = *chunk.get(2).unwrap_or(&0);
+
+        out.push(BASE64_ALPHABET[(b0 >> 2) as usize] as char);
+        out.push(BASE64_ALPHABET[(((b0 & 0x03) << 4) | (b1 >> 4)) as usize] as char);
+        out.push(if chunk.len() > 1 {
+            BASE64_ALPHABET[(((b1 & 0x0f) << 2) | (b2 >> 6)) as usize] as char
+        } else {
+            '='
+        });
+        out.push(if chunk.len() > 2 {
+            BASE64_ALPHABET[(b2 & 0x3f) as usize] as char
+        } else {
+            '='
+        });
+    }
+    out
+}
@@ -1,9 +1,79 @@
 pub mod codec;
+mod slab;
+use bytes::Bytes;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token as MioToken};
+use slab::Slab;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::Write as _;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
 use std::{cell, io, net, vec};
 use thiserror::Error;
 
-pub use codec::Codec;
-pub type ClientId = usize;
+use codec::{Advance, Negotiation};
+pub use codec::{Codec, CodecRegistry};
+
+/// A queued outgoing message: either a complete line, or one chunk of a streamed body
+/// started by [`Server::enqueue_stream`].
+enum Outgoing {
+    Line(String),
+    Chunk { data: Bytes, last: bool },
+}
+
+/// One slab slot's lifecycle stage: a freshly accepted connection is `Negotiating` its codec
+/// (see [`Negotiation`]) before being promoted to `Ready` once that completes. Only `Ready`
+/// clients are visible through [`Server::ids`] and friends.
+enum ClientSlot {
+    /// `None` only while a `poll_negotiations` call has temporarily taken ownership of the
+    /// `Negotiation` to advance it; never observed outside that call.
+    Negotiating(Option<Negotiation>),
+    Ready(Box<dyn Codec>),
+}
+
+impl ClientSlot {
+    fn raw_fd(&self) -> RawFd {
+        match self {
+            ClientSlot::Negotiating(n) => n
+                .as_ref()
+                .expect("negotiation temporarily taken")
+                .raw_fd(),
+            ClientSlot::Ready(c) => c.raw_fd(),
+        }
+    }
+}
+
+/// Identifies a client across its lifetime. The `generation` component changes whenever a
+/// slab slot is reused, so an id held by a caller can never alias a different, later client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientId {
+    index: usize,
+    generation: u32,
+}
+
+impl ClientId {
+    fn new(index: usize, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    fn index(&self) -> usize {
+        self.index
+    }
+
+    fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.index)
+    }
+}
+
+/// Reserved for the listener socket; no client slab index ever reaches `usize::MAX`.
+const LISTENER_TOKEN: MioToken = MioToken(usize::MAX);
 
 #[derive(Error, Debug)]
 pub enum ServerError {
@@ -36,33 +106,59 @@ pub enum ServerError {
 #[derive(Debug)]
 pub enum Event {
     Join(ClientId),
+    /// A newly accepted client negotiated which codec protocol it will speak.
+    Negotiated(ClientId, String),
     Leave(ClientId),
     Receive((ClientId, String)),
+    /// One chunk of a streamed body (see [`Server::enqueue_stream`]); `last` marks the chunk
+    /// that ends the stream.
+    ReceiveChunk(ClientId, Bytes, bool),
     Send(ClientId),
+    /// A connection was refused because `max_connections` was reached.
+    Rejected(net::SocketAddr),
     ClientError((ClientId, io::Error)),
     ServerError(io::Error),
 }
 
-#[derive(Debug)]
-pub struct Server<C: Codec> {
+pub struct Server {
     listener: net::TcpListener,
-    codecs: cell::RefCell<Vec<C>>,
+    registry: CodecRegistry,
+    max_connections: Option<usize>,
+    poll: cell::RefCell<Poll>,
+    mio_events: cell::RefCell<Events>,
+    codecs: cell::RefCell<Slab<ClientSlot>>,
     events: cell::RefCell<Vec<Event>>,
-    message_queue: cell::RefCell<Vec<(ClientId, String)>>,
-    last_id: cell::RefCell<ClientId>,
+    message_queue: cell::RefCell<Vec<(ClientId, Outgoing)>>,
 }
 
-impl<C: Codec> Server<C> {
-    pub fn new(addr: &str) -> Result<Self, ServerError> {
+impl Server {
+    pub fn new(
+        addr: &str,
+        registry: CodecRegistry,
+        max_connections: Option<usize>,
+    ) -> Result<Self, ServerError> {
         match net::TcpListener::bind(addr) {
             Ok(listener) => match listener.set_nonblocking(true) {
-                Ok(_) => Ok(Self {
-                    listener,
-                    codecs: cell::RefCell::new(vec![]),
-                    events: cell::RefCell::new(vec![]),
-                    message_queue: cell::RefCell::new(vec![]),
-                    last_id: cell::RefCell::new(0),
-                }),
+                Ok(_) => {
+                    let poll = Poll::new().map_err(ServerError::Listener)?;
+                    poll.registry()
+                        .register(
+                            &mut SourceFd(&listener.as_raw_fd()),
+                            LISTENER_TOKEN,
+                            Interest::READABLE,
+                        )
+                        .map_err(ServerError::Listener)?;
+                    Ok(Self {
+                        listener,
+                        registry,
+                        max_connections,
+                        poll: cell::RefCell::new(poll),
+                        mio_events: cell::RefCell::new(Events::with_capacity(128)),
+                        codecs: cell::RefCell::new(Slab::new()),
+                        events: cell::RefCell::new(vec![]),
+                        message_queue: cell::RefCell::new(vec![]),
+                    })
+                }
                 Err(e) => Err(ServerError::Listener(e)),
             },
             Err(e) => Err(ServerError::Bind {
@@ -72,16 +168,107 @@ impl<C: Codec> Server<C> {
         }
     }
 
+    /// Blocks until a socket becomes ready or a queued write needs flushing.
     pub fn poll(&self) -> Vec<Event> {
-        self.codecs.borrow_mut().retain(|c| c.is_open());
+        self.poll_timeout(None)
+    }
 
-        self.send_messages();
-        self.poll_clients();
-        self.poll_listener();
+    /// Like [`Server::poll`], but parks for at most `timeout` (blocks indefinitely if `None`).
+    /// Queued outgoing messages shorten the wait to 1ms so they get flushed promptly.
+    pub fn poll_timeout(&self, timeout: Option<Duration>) -> Vec<Event> {
+        self.evict_closed();
+        self.evict_stale_negotiations();
+
+        let wait = if self.message_queue.borrow().is_empty() {
+            timeout
+        } else {
+            Some(Duration::from_millis(1))
+        };
+
+        let mut mio_events = self.mio_events.borrow_mut();
+        match self.poll.borrow_mut().poll(&mut mio_events, wait) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => self.events.borrow_mut().push(Event::ServerError(e)),
+        }
+
+        let mut listener_readable = false;
+        let mut readable = HashSet::new();
+        for ev in mio_events.iter() {
+            if ev.token() == LISTENER_TOKEN {
+                listener_readable = true;
+            } else {
+                readable.insert(ev.token().0);
+            }
+        }
+        drop(mio_events);
+
+        if listener_readable {
+            self.poll_listener();
+        }
+        self.poll_negotiations(&readable);
+        self.poll_clients(&readable);
+        self.on_idle();
 
         self.events.borrow_mut().drain(..).collect()
     }
 
+    /// Runs after events have been drained for this tick; flushes the outgoing queue.
+    fn on_idle(&self) {
+        self.send_messages();
+    }
+
+    fn evict_closed(&self) {
+        let closed: Vec<ClientId> = self
+            .codecs
+            .borrow()
+            .iter()
+            .filter_map(|(id, slot)| match slot {
+                ClientSlot::Ready(c) if !c.is_open() => Some(id),
+                _ => None,
+            })
+            .collect();
+
+        let registry = self.poll.borrow();
+        let mut codecs = self.codecs.borrow_mut();
+        for id in closed {
+            if let Some(slot) = codecs.get_mut(id) {
+                let _ = registry.registry().deregister(&mut SourceFd(&slot.raw_fd()));
+            }
+            codecs.remove(id);
+        }
+    }
+
+    /// Evicts any `Negotiation` that's been mid-handshake longer than its timeout allows. This
+    /// never blocks the poll loop itself — a stalled client just sits here occupying its own slot until this sweep reaps it, so every other
+    /// client's reads and writes are unaffected in the meantime.
+    fn evict_stale_negotiations(&self) {
+        let expired: Vec<ClientId> = self
+            .codecs
+            .borrow()
+            .iter()
+            .filter_map(|(id, slot)| match slot {
+                ClientSlot::Negotiating(Some(n)) if n.is_expired() => Some(id),
+                _ => None,
+            })
+            .collect();
+
+        for id in expired {
+            let registry = self.poll.borrow();
+            let mut codecs = self.codecs.borrow_mut();
+            if let Some(slot) = codecs.get_mut(id) {
+                let _ = registry.registry().deregister(&mut SourceFd(&slot.raw_fd()));
+            }
+            codecs.remove(id);
+            drop(codecs);
+            drop(registry);
+            self.events.borrow_mut().push(Event::ClientError((
+                id,
+                io::Error::new(io::ErrorKind::TimedOut, "negotiation timed out"),
+            )));
+        }
+    }
+
     fn poll_listener(&self) {
         for stream in self.listener.incoming() {
             match stream {
@@ -93,70 +280,214 @@ impl<C: Codec> Server<C> {
                     self.events.borrow_mut().push(Event::ServerError(e));
                     break;
                 }
-                Ok(stream) => match self.try_accept(stream) {
-                    Ok(codec) => {
-                        self.events.borrow_mut().push(Event::Join(codec.id()));
-                        self.codecs.borrow_mut().push(codec);
+                Ok(stream) => {
+                    if self.at_capacity() {
+                        self.reject(stream);
+                        continue;
                     }
-                    Err(e) => {
-                        self.events.borrow_mut().push(Event::ServerError(e));
+                    match self.start_negotiation(stream) {
+                        Ok(id) => self.events.borrow_mut().push(Event::Join(id)),
+                        Err(e) => {
+                            self.events.borrow_mut().push(Event::ServerError(e));
+                        }
                     }
-                },
+                }
             }
         }
     }
 
-    fn try_accept(&self, stream: net::TcpStream) -> io::Result<C> {
-        stream.set_nonblocking(true)?;
-        let id = self.next_id();
-        let codec = C::new(id, stream)?;
-        Ok(codec)
+    fn at_capacity(&self) -> bool {
+        matches!(self.max_connections, Some(max) if self.codecs.borrow().len() >= max)
     }
 
-    fn poll_clients(&self) {
-        for codec in self.codecs.borrow_mut().iter_mut() {
-            match codec.read() {
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => { /* no pending data */ }
-                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                    /* assume client left voluntarily */
-                    codec.shutdown();
-                    self.events.borrow_mut().push(Event::Leave(codec.id()));
-                }
-                Ok(data) => {
-                    let m = (codec.id(), data);
-                    self.events.borrow_mut().push(Event::Receive(m));
+    fn reject(&self, mut stream: net::TcpStream) {
+        let addr = stream.peer_addr().ok();
+        let _ = writeln!(stream, "server full");
+        let _ = stream.shutdown(net::Shutdown::Both);
+        if let Some(addr) = addr {
+            self.events.borrow_mut().push(Event::Rejected(addr));
+        }
+    }
+
+    /// Reserves a slot and starts a [`Negotiation`] for a freshly accepted connection,
+    /// registering it with mio for both directions — `Sniffing`/`AwaitingChoice` need to read,
+    /// `Offering`/`Confirming` need to write. [`Server::poll_negotiations`] drives it forward
+    /// from readiness events instead of blocking here, so a slow or malicious connect can't
+    /// stall any other client.
+    fn start_negotiation(&self, stream: net::TcpStream) -> io::Result<ClientId> {
+        let id = self.codecs.borrow_mut().reserve();
+        match Negotiation::new(id, stream) {
+            Ok(negotiation) => {
+                self.poll.borrow().registry().register(
+                    &mut SourceFd(&negotiation.raw_fd()),
+                    MioToken(id.index()),
+                    Interest::READABLE.add(Interest::WRITABLE),
+                )?;
+                self.codecs
+                    .borrow_mut()
+                    .fill(id, ClientSlot::Negotiating(Some(negotiation)));
+                Ok(id)
+            }
+            Err(e) => {
+                self.codecs.borrow_mut().remove(id);
+                Err(e)
+            }
+        }
+    }
+
+    /// Advances any in-progress `Negotiation` whose token was reported ready this tick (for
+    /// either direction — `advance` sorts out which). Completing one promotes its slot to
+    /// `ClientSlot::Ready` and fires `Event::Negotiated`; failing removes it outright.
+    fn poll_negotiations(&self, readable: &HashSet<usize>) {
+        let pending: Vec<ClientId> = self
+            .codecs
+            .borrow()
+            .iter()
+            .filter(|(id, slot)| {
+                readable.contains(&id.index()) && matches!(slot, ClientSlot::Negotiating(_))
+            })
+            .map(|(id, _)| id)
+            .collect();
+
+        for id in pending {
+            let negotiation = match self.codecs.borrow_mut().get_mut(id) {
+                Some(ClientSlot::Negotiating(slot)) => slot.take(),
+                _ => None,
+            };
+            let Some(negotiation) = negotiation else {
+                continue;
+            };
+            let fd = negotiation.raw_fd();
+
+            match negotiation.advance(&self.registry) {
+                Ok(Advance::Pending(negotiation)) => {
+                    if let Some(ClientSlot::Negotiating(slot)) = self.codecs.borrow_mut().get_mut(id) {
+                        *slot = Some(negotiation);
+                    }
                 }
-                Err(e) => {
-                    codec.shutdown();
+                Ok(Advance::Done(codec, protocol)) => {
+                    let _ = self.poll.borrow().registry().reregister(
+                        &mut SourceFd(&codec.raw_fd()),
+                        MioToken(id.index()),
+                        Interest::READABLE,
+                    );
+                    if let Some(slot) = self.codecs.borrow_mut().get_mut(id) {
+                        *slot = ClientSlot::Ready(codec);
+                    }
                     self.events
                         .borrow_mut()
-                        .push(Event::ClientError((codec.id(), e)));
+                        .push(Event::Negotiated(id, protocol));
+                }
+                Err(e) => {
+                    let _ = self
+                        .poll
+                        .borrow()
+                        .registry()
+                        .deregister(&mut SourceFd(&fd));
+                    self.codecs.borrow_mut().remove(id);
+                    self.events.borrow_mut().push(Event::ClientError((id, e)));
+                }
+            }
+        }
+    }
+
+    /// Reads only the codecs whose token was reported readable this tick, draining each
+    /// until it would block so edge-triggered readiness isn't missed.
+    fn poll_clients(&self, readable: &HashSet<usize>) {
+        for (id, codec) in self
+            .codecs
+            .borrow_mut()
+            .iter_mut()
+            .filter(|(id, _)| readable.contains(&id.index()))
+            .filter_map(|(id, slot)| match slot {
+                ClientSlot::Ready(codec) => Some((id, codec)),
+                ClientSlot::Negotiating(_) => None,
+            })
+        {
+            loop {
+                match codec.read() {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        /* assume client left voluntarily */
+                        codec.shutdown();
+                        self.events.borrow_mut().push(Event::Leave(id));
+                        break;
+                    }
+                    Ok(codec::Incoming::Line(data)) => {
+                        self.events.borrow_mut().push(Event::Receive((id, data)));
+                    }
+                    Ok(codec::Incoming::Chunk { data, last }) => {
+                        self.events
+                            .borrow_mut()
+                            .push(Event::ReceiveChunk(id, data, last));
+                    }
+                    Err(e) => {
+                        codec.shutdown();
+                        self.events.borrow_mut().push(Event::ClientError((id, e)));
+                        break;
+                    }
                 }
             }
         }
     }
 
     pub fn enqueue(&self, msg: (ClientId, String)) {
-        self.message_queue.borrow_mut().push(msg);
+        self.message_queue
+            .borrow_mut()
+            .push((msg.0, Outgoing::Line(msg.1)));
     }
 
     pub fn enqueue_many(&self, msgs: impl Iterator<Item = (ClientId, String)>) {
-        msgs.for_each(|msg| self.message_queue.borrow_mut().push(msg));
+        msgs.for_each(|msg| self.enqueue(msg));
+    }
+
+    /// Queues a streamed body, interleaving its chunks into the existing `message_queue` so
+    /// one client's large transfer doesn't starve the lines/chunks queued for everyone else.
+    pub fn enqueue_stream(&self, id: ClientId, chunks: impl Iterator<Item = Bytes>) {
+        let mut chunks = chunks.peekable();
+        let mut queue = self.message_queue.borrow_mut();
+        while let Some(data) = chunks.next() {
+            let last = chunks.peek().is_none();
+            queue.push((id, Outgoing::Chunk { data, last }));
+        }
     }
 
+    /// Flushes `message_queue`. Client sockets are nonblocking, so a write can legitimately
+    /// return `WouldBlock` under backpressure (e.g. a streamed body filling the send buffer);
+    /// that message is requeued rather than treated as fatal, and the rest of that client's
+    /// queued messages wait behind it so ordering is preserved, while other clients' sends
+    /// still go out this tick.
     fn send_messages(&self) {
-        for (id, msg) in self.message_queue.borrow_mut().drain(..) {
-            if let Some(codec) = self.codecs.borrow_mut().iter_mut().find(|c| c.id() == id) {
+        let pending: Vec<(ClientId, Outgoing)> = self.message_queue.borrow_mut().drain(..).collect();
+        let mut blocked = HashSet::new();
+        for (id, msg) in pending {
+            if blocked.contains(&id) {
+                self.message_queue.borrow_mut().push((id, msg));
+                continue;
+            }
+
+            let mut codecs = self.codecs.borrow_mut();
+            if let Some(ClientSlot::Ready(codec)) = codecs.get_mut(id) {
                 if !codec.is_open() {
                     continue; /* should generate some 'SendFail' event */
                 }
-                if let Err(e) = codec.write(msg.as_str()) {
-                    codec.shutdown();
-                    self.events
-                        .borrow_mut()
-                        .push(Event::ClientError((codec.id(), e)));
-                } else {
-                    self.events.borrow_mut().push(Event::Send(codec.id()));
+                let result = match &msg {
+                    Outgoing::Line(line) => codec.write(line.as_str()),
+                    Outgoing::Chunk { data, last } => codec.write_chunk(data, *last),
+                };
+                match result {
+                    Ok(()) => self.events.borrow_mut().push(Event::Send(id)),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        drop(codecs);
+                        blocked.insert(id);
+                        self.message_queue.borrow_mut().push((id, msg));
+                    }
+                    Err(e) => {
+                        codec.shutdown();
+                        self.events
+                            .borrow_mut()
+                            .push(Event::ClientError((id, e)));
+                    }
                 }
             } else {
                 println!(
@@ -167,21 +498,13 @@ impl<C: Codec> Server<C> {
         }
     }
 
-    fn next_id(&self) -> ClientId {
-        let mut last = self.last_id.borrow_mut();
-        if ClientId::MAX == *last {
-            panic!("Server ran out of ClientIds");
-        }
-        *last += 1;
-        *last
-    }
-
     pub fn kick(&self, id: ClientId) -> Result<(), ServerError> {
-        if let Some(codec) = self.codecs.borrow_mut().iter_mut().find(|c| c.id() == id) {
-            codec.shutdown();
-            Ok(())
-        } else {
-            Err(ServerError::IdNotFound(id))
+        match self.codecs.borrow_mut().get_mut(id) {
+            Some(ClientSlot::Ready(codec)) => {
+                codec.shutdown();
+                Ok(())
+            }
+            _ => Err(ServerError::IdNotFound(id)),
         }
     }
 
@@ -189,7 +512,10 @@ impl<C: Codec> Server<C> {
         self.codecs
             .borrow()
             .iter()
-            .map(|c| (c.id(), c.is_open()))
+            .filter_map(|(id, slot)| match slot {
+                ClientSlot::Ready(c) => Some((id, c.is_open())),
+                ClientSlot::Negotiating(_) => None,
+            })
             .collect()
     }
 
@@ -197,8 +523,10 @@ impl<C: Codec> Server<C> {
         self.codecs
             .borrow()
             .iter()
-            .filter(|&c| c.is_open())
-            .map(|c| c.id())
+            .filter_map(|(id, slot)| match slot {
+                ClientSlot::Ready(c) if c.is_open() => Some(id),
+                _ => None,
+            })
             .collect()
     }
 
@@ -206,8 +534,10 @@ impl<C: Codec> Server<C> {
         self.codecs
             .borrow()
             .iter()
-            .filter(|&c| !c.is_open())
-            .map(|c| c.id())
+            .filter_map(|(id, slot)| match slot {
+                ClientSlot::Ready(c) if !c.is_open() => Some(id),
+                _ => None,
+            })
             .collect()
     }
 
@@ -0,0 +1,93 @@
+use crate::ClientId;
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+/// Generational slab storing client state by index, so a `ClientId` from a departed
+/// client can never be confused with a new client that reused its slot.
+pub(crate) struct Slab<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+}
+
+impl<T> Slab<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            slots: vec![],
+            free: vec![],
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    /// Reserves a slot and hands back its `ClientId` before the value is known, so the id
+    /// can be threaded through fallible construction (e.g. a codec negotiation handshake).
+    pub(crate) fn reserve(&mut self) -> ClientId {
+        if let Some(index) = self.free.pop() {
+            let generation = self.slots[index].generation + 1;
+            self.slots[index] = Slot {
+                generation,
+                value: None,
+            };
+            ClientId::new(index, generation)
+        } else {
+            let index = self.slots.len();
+            self.slots.push(Slot {
+                generation: 0,
+                value: None,
+            });
+            ClientId::new(index, 0)
+        }
+    }
+
+    pub(crate) fn fill(&mut self, id: ClientId, value: T) {
+        if let Some(slot) = self.slot_mut(id) {
+            slot.value = Some(value);
+        }
+    }
+
+    /// Only frees `id`'s slot if `id` actually matched the slot's current generation —
+    /// a stale `ClientId` from a departed client must not push a live occupant's index onto
+    /// `free`, which would let the next `reserve()` silently steal that occupant's slot.
+    pub(crate) fn remove(&mut self, id: ClientId) -> Option<T> {
+        match self.slot_mut(id) {
+            Some(slot) => {
+                let value = slot.value.take();
+                self.free.push(id.index());
+                value
+            }
+            None => None,
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, id: ClientId) -> Option<&mut T> {
+        self.slot_mut(id).and_then(|slot| slot.value.as_mut())
+    }
+
+    fn slot_mut(&mut self, id: ClientId) -> Option<&mut Slot<T>> {
+        self.slots
+            .get_mut(id.index())
+            .filter(|slot| slot.generation == id.generation())
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (ClientId, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.value
+                .as_ref()
+                .map(|v| (ClientId::new(index, slot.generation), v))
+        })
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = (ClientId, &mut T)> {
+        self.slots.iter_mut().enumerate().filter_map(|(index, slot)| {
+            let generation = slot.generation;
+            slot.value
+                .as_mut()
+                .map(|v| (ClientId::new(index, generation), v))
+        })
+    }
+}